@@ -0,0 +1,346 @@
+//! Support for binary-format `COPY` query execution with futures-based
+//! clients such as tokio-postgres.
+use futures::{Async, Poll, Stream};
+use postgres::types::{Type, ToSql};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::mem;
+
+use {check_types, write_header, write_i16, write_value, BinaryCopyError, Decoder, ValueSink};
+
+const CHUNK_SIZE: usize = 4096;
+
+/// A `Stream` adaptor that generates binary-formatted output for use with
+/// `COPY ... FROM STDIN (FORMAT binary)` statements.
+///
+/// It is the asynchronous counterpart of `BinaryCopyReader`, taking values
+/// from a `Stream` rather than a `StreamingIterator` and yielding chunks of
+/// encoded data.
+///
+/// If a value cannot be encoded, or `stream` returns an error, the error is
+/// returned and the stream cannot be continued past the incomplete tuple, so
+/// all subsequent polls return `BinaryCopyError::Poisoned`.
+pub struct BinaryCopyStream<S> {
+    types: Vec<Type>,
+    stream: S,
+    idx: usize,
+    buf: Vec<u8>,
+    done: bool,
+    poisoned: bool,
+}
+
+impl<S> fmt::Debug for BinaryCopyStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BinaryCopyStream")
+            .field("types", &self.types)
+            .field("stream", &self.stream)
+            .field("idx", &self.idx)
+            .field("buf", &self.buf.len())
+            .field("done", &self.done)
+            .field("poisoned", &self.poisoned)
+            .finish()
+    }
+}
+
+impl<S> BinaryCopyStream<S>
+where
+    S: Stream<Item = Box<ToSql + Send>>,
+    S::Error: From<BinaryCopyError>,
+{
+    /// Creates a new `BinaryCopyStream`.
+    ///
+    /// The stream will output tuples with a structure described by `types`
+    /// and values from `stream`. `stream` should return values in row-major
+    /// order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `types` is empty or has more than `i16::max_value()` types.
+    pub fn new(types: Vec<Type>, stream: S) -> BinaryCopyStream<S> {
+        check_types(&types);
+
+        let mut buf = vec![];
+        write_header(&mut buf, false, &[]);
+
+        BinaryCopyStream {
+            types: types,
+            stream: stream,
+            idx: 0,
+            buf: buf,
+            done: false,
+            poisoned: false,
+        }
+    }
+
+    fn take_buf(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        Ok(Async::Ready(Some(mem::replace(&mut self.buf, vec![]))))
+    }
+
+    fn poll_values(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        loop {
+            let value = match self.stream.poll()? {
+                Async::Ready(Some(value)) => value,
+                Async::Ready(None) => {
+                    if self.idx != 0 {
+                        return Err(
+                            BinaryCopyError::IncompleteRow {
+                                expected: self.types.len(),
+                                actual: self.idx,
+                            }.into(),
+                        );
+                    }
+                    let _ = write_i16(&mut self.buf, -1);
+                    self.done = true;
+                    return self.take_buf();
+                }
+                Async::NotReady => {
+                    if self.buf.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    return self.take_buf();
+                }
+            };
+
+            if self.idx == 0 {
+                // checked at construction
+                let _ = write_i16(&mut self.buf, self.types.len() as i16);
+            }
+            write_value(&mut self.buf, &self.types[self.idx], &*value)?;
+            self.idx = (self.idx + 1) % self.types.len();
+
+            if self.idx == 0 && self.buf.len() >= CHUNK_SIZE {
+                return self.take_buf();
+            }
+        }
+    }
+}
+
+impl<S> Stream for BinaryCopyStream<S>
+where
+    S: Stream<Item = Box<ToSql + Send>>,
+    S::Error: From<BinaryCopyError>,
+{
+    type Item = Vec<u8>;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+        if self.poisoned {
+            return Err(BinaryCopyError::Poisoned.into());
+        }
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        let result = self.poll_values();
+        if result.is_err() {
+            // part of the tuple may already have been returned, so the
+            // stream can't be continued
+            self.poisoned = true;
+            self.buf.clear();
+        }
+        result
+    }
+}
+
+/// A tuple's values, with `None` representing `NULL`.
+pub type Row = Vec<Option<Vec<u8>>>;
+
+#[derive(Debug, Default)]
+struct RowSink {
+    rows: VecDeque<Row>,
+    current: Row,
+    columns: usize,
+}
+
+impl RowSink {
+    fn push(&mut self, value: Option<Vec<u8>>) {
+        self.current.push(value);
+        if self.current.len() == self.columns {
+            let row = mem::replace(&mut self.current, vec![]);
+            self.rows.push_back(row);
+        }
+    }
+}
+
+impl ValueSink for RowSink {
+    fn value(&mut self, r: &[u8], _: u64, _: usize) -> io::Result<()> {
+        self.push(Some(r.to_vec()));
+        Ok(())
+    }
+
+    fn null_value(&mut self, _: u64, _: usize) -> io::Result<()> {
+        self.push(None);
+        Ok(())
+    }
+
+    fn value_chunk(&mut self, _: &[u8], _: usize, _: u64, _: usize) -> io::Result<()> {
+        unreachable!()
+    }
+
+    fn start_tuple(&mut self, columns: usize) -> io::Result<()> {
+        self.current = Vec::with_capacity(columns);
+        self.columns = columns;
+        if columns == 0 {
+            self.rows.push_back(vec![]);
+        }
+        Ok(())
+    }
+}
+
+/// A `Stream` adaptor that processes binary-formatted input from `COPY ... TO
+/// STDOUT (FORMAT binary)` statements.
+///
+/// It is the asynchronous counterpart of `BinaryCopyWriter`, taking chunks
+/// of data from a `Stream` and yielding the raw values of each tuple.
+pub struct BinaryCopyRows<S> {
+    stream: S,
+    decoder: Decoder,
+    sink: RowSink,
+}
+
+impl<S> fmt::Debug for BinaryCopyRows<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BinaryCopyRows")
+            .field("stream", &self.stream)
+            .field("decoder", &self.decoder)
+            .finish()
+    }
+}
+
+impl<S> BinaryCopyRows<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    S::Error: From<BinaryCopyError>,
+{
+    /// Creates a new `BinaryCopyRows`.
+    pub fn new(stream: S) -> BinaryCopyRows<S> {
+        BinaryCopyRows {
+            stream: stream,
+            decoder: Decoder::new(),
+            sink: RowSink::default(),
+        }
+    }
+
+    /// Consumes the `BinaryCopyRows`, returning the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S> Stream for BinaryCopyRows<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+    S::Error: From<BinaryCopyError>,
+{
+    type Item = Row;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Row>, S::Error> {
+        loop {
+            if let Some(row) = self.sink.rows.pop_front() {
+                return Ok(Async::Ready(Some(row)));
+            }
+
+            match self.stream.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    let mut chunk = chunk.as_ref();
+                    while !chunk.is_empty() {
+                        let nread = self.decoder
+                            .write(chunk, &mut self.sink)
+                            .map_err(BinaryCopyError::from)?;
+                        chunk = &chunk[nread..];
+                    }
+                }
+                Async::Ready(None) => {
+                    self.decoder.check_finished()?;
+                    return Ok(Async::Ready(None));
+                }
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{Future, Stream};
+    use futures::stream;
+    use postgres::types::{ToSql, INT4, VARCHAR};
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn stream_basic() {
+        let values: Vec<Box<ToSql + Send>> = vec![Box::new(1i32), Box::new(2i32)];
+        let values = stream::iter_ok::<_, io::Error>(values);
+        let out = BinaryCopyStream::new(vec![INT4], values)
+            .concat2()
+            .wait()
+            .unwrap();
+
+        let mut expected = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0".to_vec();
+        expected.extend_from_slice(&[0, 1, 0, 0, 0, 4, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 1, 0, 0, 0, 4, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0xff, 0xff]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn stream_poisoned() {
+        let values: Vec<Box<ToSql + Send>> = vec![
+            Box::new(1i32),
+            Box::new(2i32),
+            Box::new(3i32),
+            Box::new("foo"),
+        ];
+        let values = stream::iter_ok::<_, io::Error>(values);
+        let mut stream = BinaryCopyStream::new(vec![INT4, VARCHAR], values);
+
+        assert!(stream.poll().is_err());
+        let err = stream.poll().unwrap_err();
+        match *err.get_ref()
+            .and_then(|e| e.downcast_ref::<BinaryCopyError>())
+            .unwrap()
+        {
+            BinaryCopyError::Poisoned => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn rows_basic() {
+        let mut data = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0".to_vec();
+        data.extend_from_slice(&[0, 2, 0, 0, 0, 1, 1, 0xff, 0xff, 0xff, 0xff]);
+        data.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 2, 3]);
+        data.extend_from_slice(&[0xff, 0xff]);
+        let chunks = data.chunks(3).map(|c| c.to_vec()).collect::<Vec<_>>();
+        let chunks = stream::iter_ok::<_, io::Error>(chunks);
+
+        let rows = BinaryCopyRows::new(chunks).collect().wait().unwrap();
+        assert_eq!(
+            rows,
+            [
+                vec![Some(vec![1]), None],
+                vec![Some(vec![]), Some(vec![2, 3])],
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_truncated() {
+        let data = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0\0\x01".to_vec();
+        let chunks = stream::iter_ok::<_, io::Error>(vec![data]);
+
+        assert!(BinaryCopyRows::new(chunks).collect().wait().is_err());
+    }
+}
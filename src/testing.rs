@@ -0,0 +1,57 @@
+//! Utilities for testing `ToSql` and `FromSql` implementations against the
+//! binary format without a database connection.
+use postgres::types::{Type, ToSql};
+use std::io;
+
+use {BinaryCopyReader, OfflineBinaryCopyWriter, RowCollector};
+
+/// A decoded tuple's raw values, with `None` representing `NULL`.
+pub type DecodedRow = Vec<Option<Vec<u8>>>;
+
+/// Encodes `rows` with a `BinaryCopyReader` and decodes the result with an
+/// `OfflineBinaryCopyWriter`, returning the raw values of each tuple.
+///
+/// The values can be checked against the expected encoding directly, or
+/// passed to `FromSql::from_sql` along with the corresponding type.
+pub fn round_trip(
+    types: &[Type],
+    rows: &[&[&(ToSql + 'static)]],
+) -> io::Result<Vec<DecodedRow>> {
+    let mut reader = BinaryCopyReader::new_rows(types, rows)?;
+    let mut writer = OfflineBinaryCopyWriter::new(RowCollector::new());
+    io::copy(&mut reader, &mut writer)?;
+
+    if !writer.finished() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stream ended before its trailer",
+        ));
+    }
+
+    Ok(writer.into_inner().into_rows())
+}
+
+#[cfg(test)]
+mod test {
+    use postgres::types::{FromSql, ToSql, INT4, VARCHAR};
+
+    use super::*;
+
+    #[test]
+    fn round_trip_basic() {
+        let types = &[INT4, VARCHAR];
+        let rows: &[&[&ToSql]] = &[&[&1i32, &"foo"], &[&2i32, &None::<String>]];
+
+        let out = round_trip(types, rows).unwrap();
+        assert_eq!(
+            out,
+            [
+                vec![Some(vec![0, 0, 0, 1]), Some(b"foo".to_vec())],
+                vec![Some(vec![0, 0, 0, 2]), None],
+            ]
+        );
+
+        let id = i32::from_sql(&INT4, out[1][0].as_ref().unwrap()).unwrap();
+        assert_eq!(id, 2);
+    }
+}